@@ -1,11 +1,13 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
 use std::path::Path;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use rstar::{RTree, RTreeObject, AABB, PointDistance};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Struct to represent a single star entry from CSV.
 /// Note: The field names are case-sensitive and must match the CSV headers.
@@ -22,28 +24,96 @@ struct Star {
     z: Option<f64>,
 }
 
+/// Converts an (ra, dec) pair in degrees to a unit vector in Cartesian space.
+///
+/// This lets the R*-tree index stars on the surface of the celestial sphere
+/// instead of on a flat (ra, dec) plane, so neighbour searches are correct
+/// near the poles and across the RA=0/360 seam.
+fn radec_to_xyz(ra_deg: f64, dec_deg: f64) -> [f64; 3] {
+    let ra = ra_deg.to_radians();
+    let dec = dec_deg.to_radians();
+    [dec.cos() * ra.cos(), dec.cos() * ra.sin(), dec.sin()]
+}
+
+/// Converts a squared chord distance between two points on the unit sphere
+/// into the true angular separation, in radians.
+fn chord_to_angular_separation_rad(chord_sq: f64) -> f64 {
+    // Floating-point error on the unit-vector subtraction can push chord_sq
+    // a few ULPs past 4.0 for near-antipodal pairs, which would otherwise
+    // send asin's argument above 1.0 and yield NaN.
+    2.0 * (chord_sq.sqrt() / 2.0).min(1.0).asin()
+}
+
+/// Converts an angular search radius, in radians, into the squared chord
+/// distance on the unit sphere that `locate_within_distance` expects.
+fn angular_radius_to_chord_sq(theta_rad: f64) -> f64 {
+    let chord = 2.0 * (theta_rad / 2.0).sin();
+    chord * chord
+}
+
+/// Squared chord distance between two points on the unit sphere.
+fn chord_sq_between(a: [f64; 3], b: [f64; 3]) -> f64 {
+    let dx = a[0] - b[0];
+    let dy = a[1] - b[1];
+    let dz = a[2] - b[2];
+    dx * dx + dy * dy + dz * dz
+}
+
 /// Struct to wrap Star for spatial indexing
 #[derive(Clone, Debug)] // Added Debug for testing
 struct StarPoint {
     star: Star,
+    xyz: [f64; 3],
+}
+
+impl StarPoint {
+    fn new(star: Star) -> Self {
+        let xyz = radec_to_xyz(star.ra, star.dec);
+        StarPoint { star, xyz }
+    }
 }
 
 impl RTreeObject for StarPoint {
-    type Envelope = AABB<[f64; 2]>;
+    type Envelope = AABB<[f64; 3]>;
 
     fn envelope(&self) -> Self::Envelope {
-        AABB::from_point([self.star.ra, self.star.dec])
+        AABB::from_point(self.xyz)
     }
 }
 
 impl PointDistance for StarPoint {
-    fn distance_2(&self, point: &[f64; 2]) -> f64 {
-        let dx = self.star.ra - point[0];
-        let dy = self.star.dec - point[1];
-        dx * dx + dy * dy // Euclidean distance squared
+    fn distance_2(&self, point: &[f64; 3]) -> f64 {
+        chord_sq_between(self.xyz, *point)
     }
 }
 
+/// How query results are rendered.
+#[derive(ValueEnum, Clone, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human-readable one-line-per-match summary (the historical format).
+    Table,
+    /// One CSV row per match, header included, suitable for piping.
+    Csv,
+    /// A JSON array of match records.
+    Json,
+}
+
+/// One row of query output: a match plus its rank and angular separation
+/// from the query point.
+#[derive(Debug, Serialize, Deserialize, PartialEq)] // Deserialize/PartialEq are for testing
+struct ResultRecord {
+    rank: usize,
+    obj_id: u64,
+    ra: f64,
+    dec: f64,
+    separation_arcsec: f64,
+    u: Option<f64>,
+    g: Option<f64>,
+    r: Option<f64>,
+    i: Option<f64>,
+    z: Option<f64>,
+}
+
 /// CLI Arguments
 #[derive(Parser, Debug)]
 #[command(name = "Star Indexer", version, about = "Find nearest stars using R*-tree from a CSV file.")]
@@ -63,59 +133,578 @@ struct Args {
     /// Number of nearest neighbors to return.
     #[arg(short, long, default_value_t = 5)]
     n: usize,
+
+    /// Cone-search radius in arcminutes. When set, every star within this
+    /// angular radius is returned (sorted by separation) instead of the
+    /// fixed `--n` nearest neighbors.
+    #[arg(long, conflicts_with = "n")]
+    radius: Option<f64>,
+
+    /// Field delimiter for the CSV file.
+    #[arg(long, default_value_t = ',')]
+    delimiter: char,
+
+    /// Skip every leading line starting with this prefix (e.g. "#") before
+    /// looking for the header row.
+    #[arg(long)]
+    comment_prefix: Option<String>,
+
+    /// Number of leading lines to unconditionally skip before the header row.
+    #[arg(long, default_value_t = 1)]
+    skip_rows: usize,
+
+    /// Comma-separated tokens that should be treated as a missing value
+    /// (e.g. "NaN,null").
+    #[arg(long, value_delimiter = ',', default_values_t = vec!["NaN".to_string(), "null".to_string(), "NULL".to_string()])]
+    null_values: Vec<String>,
+
+    /// Column name carrying the object id, if it differs from `obj_id`.
+    #[arg(long)]
+    id_column: Option<String>,
+
+    /// Column name carrying right ascension, if it differs from `ra`.
+    #[arg(long)]
+    ra_column: Option<String>,
+
+    /// Column name carrying declination, if it differs from `dec`.
+    #[arg(long)]
+    dec_column: Option<String>,
+
+    /// How to render query results.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    output_format: OutputFormat,
+
+    /// Color index to cut on, as `<band>-<band>` (e.g. "g-r"). Stars missing
+    /// either band are dropped. Use with `--min`/`--max`.
+    #[arg(long)]
+    color: Option<String>,
+
+    /// Minimum allowed value of the `--color` index.
+    #[arg(long)]
+    min: Option<f64>,
+
+    /// Maximum allowed value of the `--color` index.
+    #[arg(long)]
+    max: Option<f64>,
+
+    /// Limiting-magnitude filter as `<band>=<limit>` (e.g. "r=21.0"). Stars
+    /// fainter than the limit, or missing the band, are dropped. May be
+    /// given multiple times to filter on several bands.
+    #[arg(long = "mag-limit")]
+    mag_limit: Vec<String>,
+
+    /// Number of records to deserialize per chunk when reading the catalog.
+    /// Must be at least 1; 0 is rejected with an error before any reading starts.
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+    batch_size: usize,
+
+    /// Stream the catalog in batches of `--batch-size` instead of loading
+    /// the whole file into memory, for catalogs too large to fit at once.
+    /// The R*-tree is skipped entirely: `--n` nearest neighbors are tracked
+    /// with a bounded heap, and `--radius` matches are collected as found.
+    #[arg(long)]
+    low_memory: bool,
+}
+
+/// Returns the magnitude for the named photometric band (`u`, `g`, `r`, `i`,
+/// or `z`), or `None` if the band name is unrecognized.
+fn star_band(star: &Star, band: &str) -> Option<f64> {
+    match band {
+        "u" => star.u,
+        "g" => star.g,
+        "r" => star.r,
+        "i" => star.i,
+        "z" => star.z,
+        _ => None,
+    }
+}
+
+/// Parses a `--color` spec of the form `<band>-<band>` (e.g. "g-r").
+fn parse_color_spec(spec: &str) -> Result<(String, String), Box<dyn Error>> {
+    match spec.split_once('-') {
+        Some((a, b)) if !a.is_empty() && !b.is_empty() => Ok((a.to_string(), b.to_string())),
+        _ => Err(format!("invalid --color spec '{}', expected '<band>-<band>' (e.g. 'g-r')", spec).into()),
+    }
+}
+
+/// Parses a `--mag-limit` spec of the form `<band>=<limit>` (e.g. "r=21.0").
+fn parse_mag_limit_spec(spec: &str) -> Result<(String, f64), Box<dyn Error>> {
+    match spec.split_once('=') {
+        Some((band, limit)) if !band.is_empty() => {
+            let limit: f64 = limit.parse()?;
+            Ok((band.to_string(), limit))
+        }
+        _ => Err(format!("invalid --mag-limit spec '{}', expected '<band>=<limit>' (e.g. 'r=21.0')", spec).into()),
+    }
+}
+
+/// Parsed, ready-to-apply form of the `--color`/`--min`/`--max`/`--mag-limit`
+/// flags, so each star only needs to be checked once per filter rather than
+/// re-parsing the specs on every row.
+struct Filters {
+    color: Option<(String, String, Option<f64>, Option<f64>)>,
+    mag_limits: Vec<(String, f64)>,
+}
+
+impl Filters {
+    fn from_args(args: &Args) -> Result<Self, Box<dyn Error>> {
+        let color = match &args.color {
+            Some(spec) => {
+                let (band_a, band_b) = parse_color_spec(spec)?;
+                Some((band_a, band_b, args.min, args.max))
+            }
+            None => None,
+        };
+        let mag_limits = args
+            .mag_limit
+            .iter()
+            .map(|spec| parse_mag_limit_spec(spec))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Filters { color, mag_limits })
+    }
+
+    fn passes(&self, star: &Star) -> bool {
+        if let Some((band_a, band_b, min, max)) = &self.color {
+            if !Self::color_passes(star, band_a, band_b, *min, *max) {
+                return false;
+            }
+        }
+        self.mag_limits
+            .iter()
+            .all(|(band, limit)| Self::mag_limit_passes(star, band, *limit))
+    }
+
+    /// Whether `star`'s `band_a - band_b` color index falls within
+    /// `[min, max]`. Stars missing either band never pass.
+    fn color_passes(star: &Star, band_a: &str, band_b: &str, min: Option<f64>, max: Option<f64>) -> bool {
+        match (star_band(star, band_a), star_band(star, band_b)) {
+            (Some(a), Some(b)) => {
+                let color = a - b;
+                min.is_none_or(|m| color >= m) && max.is_none_or(|m| color <= m)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `star`'s magnitude in `band` is at or below `limit`. Stars
+    /// missing the band never pass.
+    fn mag_limit_passes(star: &Star, band: &str, limit: f64) -> bool {
+        star_band(star, band).is_some_and(|m| m <= limit)
+    }
+}
+
+/// Options controlling how [`load_stars_from_csv_with_options`] parses a
+/// catalog file. Mirrors the options-builder pattern used by tools like
+/// polars' CSV reader: every field defaults to the historical hard-coded
+/// behavior, so callers only need to set what their catalog needs.
+#[derive(Debug, Clone)]
+struct CsvReadOptions {
+    /// Field delimiter byte.
+    delimiter: u8,
+    /// Skip every leading line starting with this prefix before the header.
+    comment_prefix: Option<String>,
+    /// Number of leading lines to unconditionally skip before the header.
+    skip_rows: usize,
+    /// Tokens that should be treated as a missing value for optional fields.
+    null_values: Vec<String>,
+    /// Column name carrying the object id, if it differs from `obj_id`.
+    id_column: Option<String>,
+    /// Column name carrying right ascension, if it differs from `ra`.
+    ra_column: Option<String>,
+    /// Column name carrying declination, if it differs from `dec`.
+    dec_column: Option<String>,
+}
+
+impl Default for CsvReadOptions {
+    fn default() -> Self {
+        CsvReadOptions {
+            delimiter: b',',
+            comment_prefix: None,
+            skip_rows: 1, // preserves the historical "skip line 1" behavior
+            null_values: Vec::new(),
+            id_column: None,
+            ra_column: None,
+            dec_column: None,
+        }
+    }
+}
+
+/// Replaces any field matching one of `null_values` with an empty string, so
+/// it deserializes as `None` for `Option<f64>` fields the same way an empty
+/// CSV field already does.
+fn normalize_nulls(record: &csv::StringRecord, null_values: &[String]) -> csv::StringRecord {
+    record
+        .iter()
+        .map(|field| if null_values.iter().any(|n| n == field) { "" } else { field })
+        .collect()
+}
+
+/// Loads star data from a CSV file using the default [`CsvReadOptions`]
+/// (skip the first line, then read a header matching the `Star` field
+/// names). Kept for callers that don't need to customize ingestion.
+#[allow(dead_code)] // only exercised by tests; main() uses load_stars_from_csv_with_options directly
+fn load_stars_from_csv<P: AsRef<Path>>(path: P) -> Result<Vec<Star>, Box<dyn Error>> {
+    load_stars_from_csv_with_options(path, &CsvReadOptions::default())
 }
 
-/// Loads star data from a CSV file.
+/// Loads star data from a CSV file according to `options`.
 ///
-/// The function expects a CSV file where:
-/// - The very first line is skipped (assumed to be a comment or BOM).
-/// - The second line is treated as the header row.
-/// - Subsequent lines contain star data with columns matching the `Star` struct fields
-///   (e.g., `obj_id`, `ra`, `dec`).
+/// Unlike the fixed "skip line 1, header on line 2, columns named
+/// `obj_id`/`ra`/`dec`" assumption this replaces, real survey exports vary
+/// in delimiter, comment conventions, null tokens, and column names, so all
+/// of those are configurable here.
 ///
 /// # Arguments
 ///
 /// * `path` - A type that implements `AsRef<Path>`, providing the path to the CSV file.
+/// * `options` - Delimiter, comment/skip, null-token, and column-name overrides.
 ///
 /// # Returns
 ///
 /// * `Ok(Vec<Star>)` - A vector of `Star` structs if loading and parsing are successful.
 /// * `Err(Box<dyn std::error::Error>)` - An error if the file cannot be opened,
 ///   read, or if CSV parsing fails.
-fn load_stars_from_csv<P: AsRef<Path>>(path: P) -> Result<Vec<Star>, Box<dyn Error>> {
+fn load_stars_from_csv_with_options<P: AsRef<Path>>(
+    path: P,
+    options: &CsvReadOptions,
+) -> Result<Vec<Star>, Box<dyn Error>> {
+    let mut stars = Vec::new();
+    load_stars_from_csv_batched(path, options, DEFAULT_BATCH_SIZE, |batch| {
+        stars.extend_from_slice(batch);
+        Ok(())
+    })?;
+    Ok(stars)
+}
+
+/// Default chunk size for [`load_stars_from_csv_batched`] when a caller
+/// (like [`load_stars_from_csv_with_options`]) doesn't care about batching.
+const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/// Opens `path`, skips `options.skip_rows` unconditional leading lines, then
+/// skips any further leading lines starting with `options.comment_prefix`,
+/// and hands back a `csv::Reader` positioned so the next line it sees is the
+/// header. The reader streams the rest of the file lazily rather than
+/// buffering it, so catalogs much larger than memory can still be read.
+fn open_csv_reader<P: AsRef<Path>>(
+    path: P,
+    options: &CsvReadOptions,
+) -> Result<csv::Reader<Box<dyn Read>>, Box<dyn Error>> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
 
-    // Skip the first line (e.g., BOM and #Table1 comment)
-    let mut first_line = String::new();
-    reader.read_line(&mut first_line)?; // Read and discard
+    for _ in 0..options.skip_rows {
+        let mut discarded = String::new();
+        if reader.read_line(&mut discarded)? == 0 {
+            break;
+        }
+    }
 
-    // Now, use the rest of the reader for csv parsing, treating the next line as header
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true) // The line after the skipped one is the header
-        .from_reader(reader);
+    let mut header_line = String::new();
+    loop {
+        header_line.clear();
+        if reader.read_line(&mut header_line)? == 0 {
+            break;
+        }
+        match &options.comment_prefix {
+            Some(prefix) if header_line.starts_with(prefix.as_str()) => continue,
+            _ => break,
+        }
+    }
 
-    let mut stars = Vec::new();
-    for result in rdr.deserialize() { // Use deserialize() directly
-        let star: Star = result?;
-        stars.push(star);
+    let chained: Box<dyn Read> = Box::new(Cursor::new(header_line.into_bytes()).chain(reader));
+    Ok(csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .has_headers(true)
+        .from_reader(chained))
+}
+
+/// Renames headers per `options.id_column`/`ra_column`/`dec_column` (so the
+/// existing serde-derived `Star` deserialization keeps matching by field
+/// name) and returns the resulting header record.
+fn resolve_headers<R: Read>(
+    rdr: &mut csv::Reader<R>,
+    options: &CsvReadOptions,
+) -> Result<csv::StringRecord, Box<dyn Error>> {
+    let mut column_overrides = HashMap::new();
+    if let Some(col) = &options.id_column {
+        column_overrides.insert(col.clone(), "obj_id".to_string());
+    }
+    if let Some(col) = &options.ra_column {
+        column_overrides.insert(col.clone(), "ra".to_string());
+    }
+    if let Some(col) = &options.dec_column {
+        column_overrides.insert(col.clone(), "dec".to_string());
     }
 
-    Ok(stars)
+    if column_overrides.is_empty() {
+        return Ok(rdr.headers()?.clone());
+    }
+    let renamed: csv::StringRecord = rdr
+        .headers()?
+        .iter()
+        .map(|h| column_overrides.get(h).cloned().unwrap_or_else(|| h.to_string()))
+        .collect();
+    rdr.set_headers(renamed.clone());
+    Ok(renamed)
+}
+
+/// Loads a catalog in fixed-size batches, invoking `on_batch` once per batch
+/// instead of materializing the whole catalog in memory. Modeled on polars'
+/// batched CSV reader: a single `csv::StringRecord` is reused across rows
+/// per the `csv` crate's own performance guidance, and only `batch_size`
+/// parsed `Star`s are held at any one time.
+fn load_stars_from_csv_batched<P, F>(
+    path: P,
+    options: &CsvReadOptions,
+    batch_size: usize,
+    mut on_batch: F,
+) -> Result<(), Box<dyn Error>>
+where
+    P: AsRef<Path>,
+    F: FnMut(&[Star]) -> Result<(), Box<dyn Error>>,
+{
+    if batch_size == 0 {
+        return Err("batch_size must be at least 1".into());
+    }
+
+    let mut rdr = open_csv_reader(path, options)?;
+    let headers = resolve_headers(&mut rdr, options)?;
+
+    let mut record = csv::StringRecord::new();
+    let mut batch = Vec::with_capacity(batch_size);
+
+    while rdr.read_record(&mut record)? {
+        let normalized;
+        let record_ref = if options.null_values.is_empty() {
+            &record
+        } else {
+            normalized = normalize_nulls(&record, &options.null_values);
+            &normalized
+        };
+        let star: Star = record_ref.deserialize(Some(&headers))?;
+        batch.push(star);
+        if batch.len() == batch_size {
+            on_batch(&batch)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        on_batch(&batch)?;
+    }
+
+    Ok(())
+}
+
+/// A candidate kept by the bounded max-heap in [`run_low_memory`]. Ordered
+/// by chord distance so the heap's root is always the farthest candidate
+/// currently kept, making it cheap to evict when a closer star arrives.
+struct HeapEntry {
+    chord_sq: f64,
+    star: Star,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.chord_sq == other.chord_sq
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.chord_sq.partial_cmp(&other.chord_sq).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Serializes `records` as CSV, matching what [`print_results`] writes to stdout.
+fn render_results_csv(records: &[ResultRecord]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for rec in records {
+        writer.serialize(rec)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// Serializes `records` as pretty-printed JSON, matching what [`print_results`]
+/// writes to stdout.
+fn render_results_json(records: &[ResultRecord]) -> Result<String, Box<dyn Error>> {
+    Ok(serde_json::to_string_pretty(records)?)
+}
+
+/// Renders `results` (already sorted by separation) in `output_format`. Only
+/// the requested payload goes to stdout; progress/diagnostic messages must
+/// use `eprintln!` so `--output-format csv`/`json` stay pipeable.
+fn print_results(results: &[(Star, f64)], output_format: &OutputFormat) -> Result<(), Box<dyn Error>> {
+    let records: Vec<ResultRecord> = results
+        .iter()
+        .enumerate()
+        .map(|(i, (star, separation_rad))| ResultRecord {
+            rank: i + 1,
+            obj_id: star.obj_id,
+            ra: star.ra,
+            dec: star.dec,
+            separation_arcsec: separation_rad.to_degrees() * 3600.0,
+            u: star.u,
+            g: star.g,
+            r: star.r,
+            i: star.i,
+            z: star.z,
+        })
+        .collect();
+
+    match output_format {
+        OutputFormat::Table => {
+            if records.is_empty() {
+                println!("No matches found within the dataset for the given coordinates.");
+            }
+            for rec in &records {
+                println!(
+                    "Match {}: obj_id: {}, RA: {:.5}, Dec: {:.5}, Separation: {:.3} arcsec ({:.6} deg)",
+                    rec.rank,
+                    rec.obj_id,
+                    rec.ra,
+                    rec.dec,
+                    rec.separation_arcsec,
+                    rec.separation_arcsec / 3600.0
+                );
+            }
+        }
+        OutputFormat::Csv => {
+            std::io::stdout().write_all(&render_results_csv(&records)?)?;
+        }
+        OutputFormat::Json => {
+            println!("{}", render_results_json(&records)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the configured nearest-neighbor or cone-search query against an
+/// in-memory R*-tree and prints the results.
+fn run_query(rtree: &RTree<StarPoint>, args: &Args) -> Result<(), Box<dyn Error>> {
+    let query = radec_to_xyz(args.ra, args.dec);
+
+    let mut results: Vec<(Star, f64)> = if let Some(radius_arcmin) = args.radius {
+        eprintln!(
+            "Searching for all stars within {} arcmin of RA: {}, Dec: {}...",
+            radius_arcmin, args.ra, args.dec
+        );
+        let theta_rad = (radius_arcmin / 60.0).to_radians();
+        let chord_sq = angular_radius_to_chord_sq(theta_rad);
+        rtree
+            .locate_within_distance(query, chord_sq)
+            .map(|point| (point.star.clone(), chord_to_angular_separation_rad(point.distance_2(&query))))
+            .collect()
+    } else {
+        eprintln!(
+            "Searching for {} nearest neighbors to RA: {}, Dec: {}...",
+            args.n, args.ra, args.dec
+        );
+        rtree
+            .nearest_neighbor_iter(&query)
+            .take(args.n)
+            .map(|point| (point.star.clone(), chord_to_angular_separation_rad(point.distance_2(&query))))
+            .collect()
+    };
+    results.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+
+    print_results(&results, &args.output_format)
+}
+
+/// Low-memory counterpart to the bulk R*-tree path: streams the catalog in
+/// `--batch-size` chunks and never holds the full catalog (filtered or not)
+/// in memory. `--n` nearest neighbors are tracked with a bounded max-heap
+/// that never grows past `n` entries; `--radius` matches are appended as
+/// found, since a cone search's result set is normally far smaller than the
+/// catalog itself.
+fn run_low_memory(args: &Args, csv_options: &CsvReadOptions) -> Result<(), Box<dyn Error>> {
+    eprintln!(
+        "Low-memory mode: streaming {} in batches of {}...",
+        args.file, args.batch_size
+    );
+    let filters = Filters::from_args(args)?;
+    let query = radec_to_xyz(args.ra, args.dec);
+    let cone_chord_sq = args.radius.map(|radius_arcmin| angular_radius_to_chord_sq((radius_arcmin / 60.0).to_radians()));
+
+    let mut total = 0usize;
+    let mut kept = 0usize;
+    let mut heap: BinaryHeap<HeapEntry> = BinaryHeap::new();
+    let mut cone_matches: Vec<(Star, f64)> = Vec::new();
+
+    load_stars_from_csv_batched(&args.file, csv_options, args.batch_size, |batch| {
+        for star in batch {
+            total += 1;
+            if !filters.passes(star) {
+                continue;
+            }
+            kept += 1;
+
+            let chord_sq = chord_sq_between(radec_to_xyz(star.ra, star.dec), query);
+
+            if let Some(max_chord_sq) = cone_chord_sq {
+                if chord_sq <= max_chord_sq {
+                    cone_matches.push((star.clone(), chord_to_angular_separation_rad(chord_sq)));
+                }
+            } else if heap.len() < args.n {
+                heap.push(HeapEntry { chord_sq, star: star.clone() });
+            } else if heap.peek().is_some_and(|farthest| chord_sq < farthest.chord_sq) {
+                heap.pop();
+                heap.push(HeapEntry { chord_sq, star: star.clone() });
+            }
+        }
+        Ok(())
+    })?;
+
+    eprintln!("Streamed {} stars ({} kept after filters).", total, kept);
+
+    let mut results: Vec<(Star, f64)> = if cone_chord_sq.is_some() {
+        cone_matches
+    } else {
+        heap.into_iter()
+            .map(|entry| (entry.star, chord_to_angular_separation_rad(entry.chord_sq)))
+            .collect()
+    };
+    results.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+
+    print_results(&results, &args.output_format)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
-    println!("Loading stars from: {}", args.file);
-    let stars = match load_stars_from_csv(&args.file) {
+    let csv_options = CsvReadOptions {
+        delimiter: args.delimiter as u8,
+        comment_prefix: args.comment_prefix.clone(),
+        skip_rows: args.skip_rows,
+        null_values: args.null_values.clone(),
+        id_column: args.id_column.clone(),
+        ra_column: args.ra_column.clone(),
+        dec_column: args.dec_column.clone(),
+    };
+
+    if args.low_memory {
+        return run_low_memory(&args, &csv_options);
+    }
+
+    let filters = Filters::from_args(&args)?;
+
+    eprintln!("Loading stars from: {}", args.file);
+    let mut stars = match load_stars_from_csv_with_options(&args.file, &csv_options) {
         Ok(s) => {
             if s.is_empty() {
                 eprintln!("Warning: No stars loaded from the CSV file. Ensure the file is not empty and format is correct.");
                 // Optionally, exit here if no stars means no work to do
-                // return Ok(()); 
+                // return Ok(());
             }
-            println!("Loaded {} stars.", s.len());
+            eprintln!("Loaded {} stars.", s.len());
             s
         }
         Err(e) => {
@@ -123,41 +712,30 @@ fn main() -> Result<(), Box<dyn Error>> {
             return Err(e);
         }
     };
-    
+
+    if let Some((band_a, band_b, min, max)) = &filters.color {
+        let before = stars.len();
+        stars.retain(|s| Filters::color_passes(s, band_a, band_b, *min, *max));
+        eprintln!("Color cut {}-{}: kept {} of {} stars.", band_a, band_b, stars.len(), before);
+    }
+    for (band, limit) in &filters.mag_limits {
+        let before = stars.len();
+        stars.retain(|s| Filters::mag_limit_passes(s, band, *limit));
+        eprintln!("Magnitude limit {}={}: kept {} of {} stars.", band, limit, stars.len(), before);
+    }
+
     if stars.is_empty() {
-        println!("No stars to index. Exiting.");
+        eprintln!("No stars to index. Exiting.");
         return Ok(());
     }
 
-    let points: Vec<StarPoint> = stars.into_iter().map(|s| StarPoint { star: s }).collect();
+    let points: Vec<StarPoint> = stars.into_iter().map(StarPoint::new).collect();
 
-    println!("Building R*-tree index...");
+    eprintln!("Building R*-tree index...");
     let rtree = RTree::bulk_load(points);
-    println!("R*-tree index built.");
-
-    println!(
-        "Searching for {} nearest neighbors to RA: {}, Dec: {}...",
-        args.n, args.ra, args.dec
-    );
-    let nearest = rtree.nearest_neighbor_iter(&[args.ra, args.dec]).take(args.n);
-
-    let mut count = 0;
-    for point in nearest {
-        count += 1;
-        println!(
-            "Neighbor {}: obj_id: {}, RA: {:.5}, Dec: {:.5}, Distance_sq: {:.5}",
-            count,
-            point.star.obj_id,
-            point.star.ra,
-            point.star.dec,
-            point.distance_2(&[args.ra, args.dec]) // Calculate actual distance for output
-        );
-    }
-    if count == 0 {
-        println!("No neighbors found within the dataset for the given coordinates.");
-    }
+    eprintln!("R*-tree index built.");
 
-    Ok(())
+    run_query(&rtree, &args)
 }
 
 #[cfg(test)]
@@ -245,6 +823,217 @@ obj_id,ra,dec,u,g,r,i,z\n";
         assert!(stars.is_empty(), "Expected an empty vector of stars, but got {} stars", stars.len());
     }
 
+    #[test]
+    fn test_radec_to_xyz_is_unit_vector() {
+        let xyz = radec_to_xyz(37.5, -12.25);
+        let norm_sq = xyz[0] * xyz[0] + xyz[1] * xyz[1] + xyz[2] * xyz[2];
+        assert!((norm_sq - 1.0).abs() < 1e-12, "expected unit vector, got norm^2={}", norm_sq);
+    }
+
+    #[test]
+    fn test_radec_to_xyz_poles_independent_of_ra() {
+        // At the pole, RA is degenerate: every RA should map to the same point.
+        let north_a = radec_to_xyz(10.0, 90.0);
+        let north_b = radec_to_xyz(250.0, 90.0);
+        for i in 0..3 {
+            assert!((north_a[i] - north_b[i]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_chord_to_angular_separation_known_values() {
+        // Same point: chord = 0 -> separation = 0.
+        assert!((chord_to_angular_separation_rad(0.0)).abs() < 1e-12);
+
+        // Antipodal points on the unit sphere: chord^2 = 4 -> separation = pi.
+        let antipodal = chord_to_angular_separation_rad(4.0);
+        assert!((antipodal - std::f64::consts::PI).abs() < 1e-9);
+
+        // Points 90 degrees apart: chord^2 = 2 -> separation = pi/2.
+        let right_angle = chord_to_angular_separation_rad(2.0);
+        assert!((right_angle - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_chord_to_angular_separation_clamps_past_antipodal() {
+        // Floating-point error on a real unit-vector subtraction can push
+        // chord_sq a few ULPs past the theoretical max of 4.0 for near-antipodal
+        // pairs. Without clamping, asin's argument exceeds 1.0 and yields NaN.
+        let past_antipodal = chord_to_angular_separation_rad(4.0 + 1e-9);
+        assert!(!past_antipodal.is_nan(), "expected a finite separation, got NaN");
+        assert!((past_antipodal - std::f64::consts::PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_angular_separation_across_ra_seam() {
+        // Two points near dec=89 deg but on opposite sides of the RA=0/360 seam
+        // are close on the sky even though their RA differs by ~180 units.
+        let a = radec_to_xyz(0.5, 89.0);
+        let b = radec_to_xyz(359.5, 89.0);
+        let dx = a[0] - b[0];
+        let dy = a[1] - b[1];
+        let dz = a[2] - b[2];
+        let chord_sq = dx * dx + dy * dy + dz * dz;
+        let separation_deg = chord_to_angular_separation_rad(chord_sq).to_degrees();
+        assert!(separation_deg < 1.0, "expected close points near the pole, got {} deg", separation_deg);
+    }
+
+    #[test]
+    fn test_angular_radius_to_chord_sq_round_trips() {
+        let theta_rad = 0.5_f64.to_radians();
+        let chord_sq = angular_radius_to_chord_sq(theta_rad);
+        let recovered = chord_to_angular_separation_rad(chord_sq);
+        assert!((recovered - theta_rad).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_load_stars_with_custom_delimiter_and_comment_prefix() {
+        let csv_content = "# dump generated 2026-01-01\n\
+# next run tomorrow\n\
+obj_id;ra;dec;u;g;r;i;z\n\
+1;150.0;2.0;18.0;17.5;17.0;16.8;16.5\n";
+
+        let temp_file = create_temp_csv(csv_content).expect("Failed to create temp CSV");
+        let options = CsvReadOptions {
+            delimiter: b';',
+            comment_prefix: Some("#".to_string()),
+            skip_rows: 0,
+            ..CsvReadOptions::default()
+        };
+        let stars = load_stars_from_csv_with_options(temp_file.path(), &options)
+            .expect("load_stars_from_csv_with_options returned an error");
+
+        assert_eq!(stars.len(), 1);
+        assert_eq!(stars[0].obj_id, 1);
+        assert_eq!(stars[0].ra, 150.0);
+    }
+
+    #[test]
+    fn test_load_stars_with_column_overrides_and_null_values() {
+        let csv_content = "id,right_ascension,declination,u,g,r,i,z\n\
+1,150.0,2.0,NaN,17.5,17.0,16.8,16.5\n";
+
+        let temp_file = create_temp_csv(csv_content).expect("Failed to create temp CSV");
+        let options = CsvReadOptions {
+            skip_rows: 0,
+            id_column: Some("id".to_string()),
+            ra_column: Some("right_ascension".to_string()),
+            dec_column: Some("declination".to_string()),
+            null_values: vec!["NaN".to_string()],
+            ..CsvReadOptions::default()
+        };
+        let stars = load_stars_from_csv_with_options(temp_file.path(), &options)
+            .expect("load_stars_from_csv_with_options returned an error");
+
+        assert_eq!(stars.len(), 1);
+        assert_eq!(stars[0].obj_id, 1);
+        assert_eq!(stars[0].ra, 150.0);
+        assert_eq!(stars[0].dec, 2.0);
+        assert_eq!(stars[0].u, None);
+    }
+
+    #[test]
+    fn test_parse_color_spec_ok() {
+        assert_eq!(parse_color_spec("g-r").unwrap(), ("g".to_string(), "r".to_string()));
+    }
+
+    #[test]
+    fn test_parse_color_spec_rejects_malformed_input() {
+        assert!(parse_color_spec("gr").is_err());
+        assert!(parse_color_spec("-r").is_err());
+        assert!(parse_color_spec("g-").is_err());
+    }
+
+    #[test]
+    fn test_parse_mag_limit_spec_ok() {
+        assert_eq!(parse_mag_limit_spec("r=21.0").unwrap(), ("r".to_string(), 21.0));
+    }
+
+    #[test]
+    fn test_parse_mag_limit_spec_rejects_malformed_input() {
+        assert!(parse_mag_limit_spec("r21.0").is_err());
+        assert!(parse_mag_limit_spec("r=not_a_number").is_err());
+    }
+
+    #[test]
+    fn test_star_band_looks_up_known_and_unknown_bands() {
+        let star = Star { obj_id: 1, ra: 0.0, dec: 0.0, u: Some(1.0), g: Some(2.0), r: Some(3.0), i: None, z: None };
+        assert_eq!(star_band(&star, "g"), Some(2.0));
+        assert_eq!(star_band(&star, "i"), None);
+        assert_eq!(star_band(&star, "not_a_band"), None);
+    }
+
+    #[test]
+    fn test_load_stars_batched_splits_into_chunks() {
+        let csv_content = "obj_id,ra,dec,u,g,r,i,z\n\
+1,150.0,2.0,,,,,\n\
+2,150.1,2.1,,,,,\n\
+3,150.2,2.2,,,,,\n\
+4,150.3,2.3,,,,,\n\
+5,150.4,2.4,,,,,\n";
+
+        let temp_file = create_temp_csv(csv_content).expect("Failed to create temp CSV");
+        let options = CsvReadOptions { skip_rows: 0, ..CsvReadOptions::default() };
+
+        let mut batch_sizes = Vec::new();
+        let mut total = 0usize;
+        load_stars_from_csv_batched(temp_file.path(), &options, 2, |batch| {
+            batch_sizes.push(batch.len());
+            total += batch.len();
+            Ok(())
+        })
+        .expect("load_stars_from_csv_batched returned an error");
+
+        assert_eq!(batch_sizes, vec![2, 2, 1]);
+        assert_eq!(total, 5);
+    }
+
+    #[test]
+    fn test_filters_passes_combines_color_and_mag_limit() {
+        let filters = Filters {
+            color: Some(("g".to_string(), "r".to_string(), Some(0.3), Some(1.2))),
+            mag_limits: vec![("r".to_string(), 21.0)],
+        };
+
+        let in_range = Star { obj_id: 1, ra: 0.0, dec: 0.0, u: None, g: Some(18.0), r: Some(17.5), i: None, z: None };
+        assert!(filters.passes(&in_range)); // color = 0.5, r = 17.5
+
+        let too_blue = Star { obj_id: 2, ra: 0.0, dec: 0.0, u: None, g: Some(17.5), r: Some(17.5), i: None, z: None };
+        assert!(!filters.passes(&too_blue)); // color = 0.0 < min 0.3
+
+        let too_faint = Star { obj_id: 3, ra: 0.0, dec: 0.0, u: None, g: Some(18.0), r: Some(22.0), i: None, z: None };
+        assert!(!filters.passes(&too_faint)); // r = 22.0 > limit 21.0
+
+        let missing_band = Star { obj_id: 4, ra: 0.0, dec: 0.0, u: None, g: None, r: Some(17.5), i: None, z: None };
+        assert!(!filters.passes(&missing_band)); // missing g for the color cut
+    }
+
+    #[test]
+    fn test_render_results_csv_has_header_and_rows() {
+        let records = vec![
+            ResultRecord { rank: 1, obj_id: 1, ra: 10.0, dec: 20.0, separation_arcsec: 0.5, u: Some(19.0), g: Some(18.0), r: Some(17.5), i: None, z: None },
+            ResultRecord { rank: 2, obj_id: 2, ra: 11.0, dec: 21.0, separation_arcsec: 3.25, u: None, g: Some(18.5), r: Some(17.0), i: Some(16.8), z: Some(16.5) },
+        ];
+
+        let csv_bytes = render_results_csv(&records).expect("render_results_csv failed");
+        let csv_text = String::from_utf8(csv_bytes).expect("CSV output was not valid UTF-8");
+        let mut lines = csv_text.lines();
+
+        assert_eq!(lines.next(), Some("rank,obj_id,ra,dec,separation_arcsec,u,g,r,i,z"));
+        assert_eq!(lines.next(), Some("1,1,10.0,20.0,0.5,19.0,18.0,17.5,,"));
+        assert_eq!(lines.next(), Some("2,2,11.0,21.0,3.25,,18.5,17.0,16.8,16.5"));
+    }
+
+    #[test]
+    fn test_render_results_json_round_trips_through_serde() {
+        let records = vec![ResultRecord { rank: 1, obj_id: 7, ra: 150.0, dec: 2.0, separation_arcsec: 1.5, u: None, g: Some(18.0), r: Some(17.5), i: None, z: None }];
+
+        let json_text = render_results_json(&records).expect("render_results_json failed");
+        let parsed: Vec<ResultRecord> = serde_json::from_str(&json_text).expect("output was not valid JSON");
+
+        assert_eq!(parsed, vec![ResultRecord { rank: 1, obj_id: 7, ra: 150.0, dec: 2.0, separation_arcsec: 1.5, u: None, g: Some(18.0), r: Some(17.5), i: None, z: None }]);
+    }
+
     #[test]
     fn test_load_stars_file_not_found() {
         let result = load_stars_from_csv(Path::new("non_existent_file.csv"));